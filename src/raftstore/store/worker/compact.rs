@@ -13,25 +13,51 @@
 
 use raftstore::store::keys;
 use raftstore::store::engine::Iterable;
-use util::worker::Runnable;
+use util::worker::{Runnable, FnBox};
 use util::rocksdb;
 use storage::{CF_RAFT, CF_LOCK};
 
-use rocksdb::{DB, WriteBatch, Writable};
+use rocksdb::{DB, WriteBatch, Writable, DBEntryType, ColumnFamilyOptions};
+use rocksdb::{TablePropertiesCollector, TablePropertiesCollectorFactory, UserCollectedProperties};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::fmt::{self, Formatter, Display};
 use std::error;
+use std::str;
+
+/// Name of the user property that stores the number of entries written to
+/// an SST, collected by `TombstoneCountCollector`.
+const PROP_NUM_ENTRIES: &'static str = "tikv.num_entries";
+/// Name of the user property that stores the number of `Delete` /
+/// `SingleDelete` entries written to an SST.
+const PROP_NUM_TOMBSTONES: &'static str = "tikv.num_tombstones";
+
+/// Callback invoked once a `Task` finishes, with the task's count (or the
+/// error it failed with): the number of raft log entries removed for
+/// `CompactRaftLog`, the number of sub-ranges compacted for
+/// `CheckAndCompact`, and always 0 for `CompactLockCF`.
+pub type Callback = Box<FnBox<Result<u64, Error>> + Send>;
 
 pub enum Task {
     CompactLockCF {
         engine: Arc<DB>,
         start_key: Vec<u8>, // empty vec means smallest key
         end_key: Vec<u8>, // empty vec means largest key
+        cb: Option<Callback>,
     },
     CompactRaftLog {
         engine: Arc<DB>,
         region_id: u64,
         compact_idx: u64,
+        cb: Option<Callback>,
+    },
+    CheckAndCompact {
+        engine: Arc<DB>,
+        cf_names: Vec<String>,
+        ranges: Vec<Vec<u8>>,
+        // Range will be skipped if tombstone percent is below this threshold.
+        tombstones_threshold: u64,
+        cb: Option<Callback>,
     },
 }
 
@@ -47,13 +73,20 @@ impl Display for Task {
             Task::CompactLockCF { ref start_key, ref end_key, .. } => {
                 write!(f, "Compact Lock CF, range[{:?}, {:?}]", start_key, end_key)
             }
+            Task::CheckAndCompact { ref cf_names, ref ranges, tombstones_threshold, .. } => {
+                write!(f,
+                       "Check and compact CF(s) {:?}, ranges count {}, tombstones threshold {}",
+                       cf_names,
+                       ranges.len(),
+                       tombstones_threshold)
+            }
         }
     }
 }
 
 quick_error! {
     #[derive(Debug)]
-    enum Error {
+    pub enum Error {
         Other(err: Box<error::Error + Sync + Send>) {
             from()
             cause(err.as_ref())
@@ -63,15 +96,142 @@ quick_error! {
     }
 }
 
-pub struct Runner;
+/// `TombstoneCountCollector` is a `TablePropertiesCollector` that tracks how
+/// many `Delete` / `SingleDelete` entries an SST file contains alongside its
+/// total entry count. It must be registered on the `write`/`default` CF
+/// options when the engine is opened so that `Runner::check_and_compact` can
+/// later read the collected counts back off the table properties without
+/// having to scan the data itself.
+pub struct TombstoneCountCollector {
+    num_entries: u64,
+    num_tombstones: u64,
+}
+
+impl Default for TombstoneCountCollector {
+    fn default() -> TombstoneCountCollector {
+        TombstoneCountCollector {
+            num_entries: 0,
+            num_tombstones: 0,
+        }
+    }
+}
+
+impl TablePropertiesCollector for TombstoneCountCollector {
+    fn add(&mut self,
+           _: &[u8],
+           _: &[u8],
+           entry_type: DBEntryType,
+           _: u64,
+           _: u64)
+           -> Result<(), String> {
+        self.num_entries += 1;
+        if entry_type == DBEntryType::Delete || entry_type == DBEntryType::SingleDelete {
+            self.num_tombstones += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<HashMap<Vec<u8>, Vec<u8>>, String> {
+        let mut props = HashMap::new();
+        props.insert(PROP_NUM_ENTRIES.as_bytes().to_owned(),
+                      self.num_entries.to_string().into_bytes());
+        props.insert(PROP_NUM_TOMBSTONES.as_bytes().to_owned(),
+                      self.num_tombstones.to_string().into_bytes());
+        Ok(props)
+    }
+}
+
+pub struct TombstoneCountCollectorFactory;
+
+impl TablePropertiesCollectorFactory for TombstoneCountCollectorFactory {
+    fn create_table_properties_collector(&mut self, _: u32) -> Box<TablePropertiesCollector> {
+        Box::new(TombstoneCountCollector::default())
+    }
+}
+
+/// Registers `TombstoneCountCollectorFactory` on `cf_opts`. The engine
+/// bootstrap code that builds the `default`/`write` CF options (outside this
+/// worker module) must call this before the engine is opened — without that
+/// call site, `check_and_compact` never sees `tikv.num_entries` /
+/// `tikv.num_tombstones` on any SST and treats every range as empty. The test
+/// below calls it directly to exercise the whole path end to end.
+pub fn register_tombstone_count_collector(cf_opts: &mut ColumnFamilyOptions) {
+    cf_opts.add_table_properties_collector_factory("tikv.tombstone-count-collector",
+                                                     Box::new(TombstoneCountCollectorFactory));
+}
+
+fn get_num(props: &UserCollectedProperties, name: &str) -> u64 {
+    props.get(name.as_bytes())
+        .and_then(|v| str::from_utf8(v).ok())
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Sums `tikv.num_entries` and `tikv.num_tombstones` across every SST whose
+/// range overlaps `[start_key, end_key)` in `cf`.
+fn collect_tombstone_stats(engine: &DB,
+                            cf: &str,
+                            start_key: &[u8],
+                            end_key: &[u8])
+                            -> Result<(u64, u64), Error> {
+    let handle = box_try!(rocksdb::get_cf_handle(engine, cf));
+    let collection =
+        box_try!(engine.get_properties_of_tables_in_range(handle, &[(start_key, end_key)]));
+    let mut num_entries = 0;
+    let mut num_tombstones = 0;
+    for (_, v) in &*collection {
+        num_entries += get_num(v.user_collected_properties(), PROP_NUM_ENTRIES);
+        num_tombstones += get_num(v.user_collected_properties(), PROP_NUM_TOMBSTONES);
+    }
+    Ok((num_entries, num_tombstones))
+}
+
+/// Whether a range's tombstone percentage reaches `tombstones_threshold`,
+/// i.e. `num_tombstones * 100 / num_entries >= threshold`. `num_entries` is
+/// `TombstoneCountCollector`'s total entry count, which already includes
+/// the tombstones themselves, so it must not be added to again here.
+fn need_compact(num_entries: u64, num_tombstones: u64, tombstones_threshold: u64) -> bool {
+    if num_entries == 0 {
+        return false;
+    }
+    num_tombstones * 100 / num_entries >= tombstones_threshold
+}
+
+/// Runs compaction tasks. Keeps track of the last `compact_idx` processed
+/// per region so that redundant `CompactRaftLog` tasks queued for the same
+/// region in quick succession can be dropped before doing any RocksDB work.
+#[derive(Default)]
+pub struct Runner {
+    compacted_idxs: HashMap<u64, u64>,
+}
 
 impl Runner {
+    pub fn new() -> Runner {
+        Runner { compacted_idxs: HashMap::new() }
+    }
+
+    /// Map of region id to the highest `compact_idx` already compacted for
+    /// that region, so callers outside `Runner` can merge pending
+    /// `CompactRaftLog` tasks against what's already been done.
+    pub fn compacted_idxs(&self) -> &HashMap<u64, u64> {
+        &self.compacted_idxs
+    }
+
     /// Do the compact job and return the count of log compacted.
     fn compact_raft_log(&mut self,
                         engine: Arc<DB>,
                         region_id: u64,
                         compact_idx: u64)
                         -> Result<u64, Error> {
+        if let Some(&last_idx) = self.compacted_idxs.get(&region_id) {
+            if compact_idx <= last_idx {
+                info!("[region {}] already compacted to {}, skip {}",
+                      region_id,
+                      last_idx,
+                      compact_idx);
+                return Ok(0);
+            }
+        }
         let start_key = keys::raft_log_key(region_id, 0);
         let mut first_idx = compact_idx;
         if let Some((k, _)) = box_try!(engine.seek_cf(CF_RAFT, &start_key)) {
@@ -79,16 +239,36 @@ impl Runner {
         }
         if first_idx >= compact_idx {
             info!("[region {}] no need to compact", region_id);
+            // Nothing left before compact_idx, so record it as done too,
+            // otherwise a burst of duplicate no-op tasks for this region
+            // would keep re-running seek_cf instead of being short-circuited
+            // by the check above.
+            self.compacted_idxs.insert(region_id, compact_idx);
             return Ok(0);
         }
         let wb = WriteBatch::new();
         let handle = box_try!(rocksdb::get_cf_handle(&engine, CF_RAFT));
-        for idx in first_idx..compact_idx {
-            let key = keys::raft_log_key(region_id, idx);
-            box_try!(wb.delete_cf(handle, &key));
+        let start_key = keys::raft_log_key(region_id, first_idx);
+        let end_key = keys::raft_log_key(region_id, compact_idx);
+        // Issue a single range tombstone instead of `compact_idx - first_idx`
+        // point tombstones: a region that stalled its leader for a while can
+        // have millions of log entries to trim, and per-key deletes both
+        // bloat the write batch and leave iterators over CF_RAFT stepping
+        // over a tombstone per trimmed entry.
+        if let Err(e) = wb.delete_range_cf(handle, &start_key, &end_key) {
+            // Range deletion isn't supported everywhere (e.g. plain table
+            // format), so fall back to the old per-key batch in that case.
+            warn!("[region {}] delete_range_cf failed, fall back to per-key deletes: {:?}",
+                  region_id,
+                  e);
+            for idx in first_idx..compact_idx {
+                let key = keys::raft_log_key(region_id, idx);
+                box_try!(wb.delete_cf(handle, &key));
+            }
         }
         // It's not safe to disable WAL here. We may lost data after crashed for unknown reason.
         box_try!(engine.write(wb));
+        self.compacted_idxs.insert(region_id, compact_idx);
         Ok(compact_idx - first_idx)
     }
 
@@ -101,26 +281,157 @@ impl Runner {
         engine.compact_range_cf(cf_handle, start_key, end_key);
         Ok(())
     }
+
+    /// Check each `[ranges[i], ranges[i + 1])` sub-range of every CF in
+    /// `cf_names` and only issue a `compact_range_cf` where the tombstone
+    /// percentage reaches `tombstones_threshold`, instead of blindly
+    /// compacting the whole CF. Returns the number of sub-ranges actually
+    /// compacted.
+    fn check_and_compact(&mut self,
+                         engine: Arc<DB>,
+                         cf_names: Vec<String>,
+                         ranges: Vec<Vec<u8>>,
+                         tombstones_threshold: u64)
+                         -> Result<u64, Error> {
+        if ranges.len() < 2 {
+            return Ok(0);
+        }
+        let mut compacted_ranges = 0;
+        for cf in &cf_names {
+            let handle = box_try!(rocksdb::get_cf_handle(&engine, cf));
+            for w in ranges.windows(2) {
+                let (start_key, end_key) = (&w[0], &w[1]);
+                let (num_entries, num_tombstones) =
+                    box_try!(collect_tombstone_stats(&engine, cf, start_key, end_key));
+                if !need_compact(num_entries, num_tombstones, tombstones_threshold) {
+                    continue;
+                }
+                info!("[cf {}] range [{:?}, {:?}] has {} tombstones out of {} entries, \
+                       compacting",
+                      cf,
+                      start_key,
+                      end_key,
+                      num_tombstones,
+                      num_entries);
+                engine.compact_range_cf(handle, start_key, end_key);
+                compacted_ranges += 1;
+            }
+        }
+        Ok(compacted_ranges)
+    }
 }
 
 impl Runnable<Task> for Runner {
     fn run(&mut self, task: Task) {
         match task {
-            Task::CompactRaftLog { engine, region_id, compact_idx } => {
+            Task::CompactRaftLog { engine, region_id, compact_idx, cb } => {
                 debug!("[region {}] execute compacting log to {}",
                        region_id,
                        compact_idx);
-                match self.compact_raft_log(engine.clone(), region_id, compact_idx) {
-                    Err(e) => error!("[region {}] failed to compact: {:?}", region_id, e),
+                let res = self.compact_raft_log(engine.clone(), region_id, compact_idx);
+                match res {
+                    Err(ref e) => error!("[region {}] failed to compact: {:?}", region_id, e),
                     Ok(n) => info!("[region {}] compact {} log entries", region_id, n),
                 }
+                if let Some(cb) = cb {
+                    cb.call_box(res);
+                }
             }
-            Task::CompactLockCF { engine, start_key, end_key } => {
+            Task::CompactLockCF { engine, start_key, end_key, cb } => {
                 debug!("execute compact lock cf");
-                if let Err(e) = self.compact_lock_cf(engine, &start_key, &end_key) {
+                let res = self.compact_lock_cf(engine, &start_key, &end_key).map(|_| 0);
+                if let Err(ref e) = res {
                     error!("execute compact lock cf failed, err {}", e);
                 }
+                if let Some(cb) = cb {
+                    cb.call_box(res);
+                }
+            }
+            Task::CheckAndCompact { engine, cf_names, ranges, tombstones_threshold, cb } => {
+                debug!("execute check and compact, cf(s) {:?}", cf_names);
+                let res = self.check_and_compact(engine, cf_names, ranges, tombstones_threshold);
+                if let Err(ref e) = res {
+                    error!("execute check and compact failed, err {}", e);
+                }
+                if let Some(cb) = cb {
+                    cb.call_box(res);
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_tombstone_stats, need_compact, register_tombstone_count_collector, rocksdb,
+                Runner};
+    use rocksdb::{CFOptions, ColumnFamilyOptions, DBOptions, DB, Writable};
+    use std::sync::Arc;
+    use storage::CF_DEFAULT;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_need_compact() {
+        // 90 tombstones out of 100 total entries: 90% >= 50% threshold.
+        assert!(need_compact(100, 90, 50));
+        // 10 tombstones out of 100 total entries: 10% < 50% threshold.
+        assert!(!need_compact(100, 10, 50));
+        // Exactly at the threshold should still trigger compaction.
+        assert!(need_compact(100, 50, 50));
+        // No data collected at all: nothing to compact.
+        assert!(!need_compact(0, 0, 50));
+    }
+
+    fn new_test_engine() -> Arc<DB> {
+        let dir = TempDir::new("compact-worker-test").unwrap();
+        Arc::new(DB::open_default(dir.path().to_str().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_compact_raft_log_skips_already_compacted() {
+        let engine = new_test_engine();
+        let mut runner = Runner::new();
+        runner.compacted_idxs.insert(1, 100);
+
+        // region 1 is already compacted past 50, so this is a no-op that
+        // doesn't even need to seek_cf.
+        let n = runner.compact_raft_log(engine, 1, 50).unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn test_check_and_compact_with_real_engine() {
+        let dir = TempDir::new("compact-worker-test").unwrap();
+        let mut cf_opts = ColumnFamilyOptions::new();
+        register_tombstone_count_collector(&mut cf_opts);
+        let engine = Arc::new(rocksdb::new_engine_opt(dir.path().to_str().unwrap(),
+                                                       DBOptions::new(),
+                                                       vec![CFOptions::new(CF_DEFAULT, cf_opts)])
+            .unwrap());
+        let handle = rocksdb::get_cf_handle(&engine, CF_DEFAULT).unwrap();
+
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            engine.put_cf(handle, key.as_bytes(), b"v").unwrap();
+        }
+        for i in 0..90 {
+            let key = format!("key{:04}", i);
+            engine.delete_cf(handle, key.as_bytes()).unwrap();
+        }
+        engine.flush_cf(handle, true).unwrap();
+
+        let (num_entries, num_tombstones) =
+            collect_tombstone_stats(&engine, CF_DEFAULT, b"key0000", b"key9999").unwrap();
+        assert_eq!(num_entries, 100);
+        assert_eq!(num_tombstones, 90);
+        assert!(need_compact(num_entries, num_tombstones, 50));
+
+        let mut runner = Runner::new();
+        let compacted = runner.check_and_compact(engine,
+                                   vec![CF_DEFAULT.to_owned()],
+                                   vec![b"key0000".to_vec(), b"key9999".to_vec()],
+                                   50)
+            .unwrap();
+        assert_eq!(compacted, 1);
+    }
+}